@@ -1,42 +1,553 @@
 use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::io::{self, prelude::*, Cursor};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use crate::Error;
 
-pub(crate) fn stdin() -> Result<(i64, Value), Error> {
+/// A single native-messaging request, decoded from the `method` tag carried
+/// alongside `rpc_id` in the frame. Each supported operation gets its own
+/// variant with its own typed parameters instead of callers hand-extracting
+/// fields from a loose [`Value`] with macros like `as_i64!`. A `method` we
+/// don't recognize decodes to [`Request::Unknown`] instead of failing to
+/// deserialize, so callers can reply with a structured error instead of
+/// panicking.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub(crate) enum Request {
+    Ping,
+    Version,
+    Search {
+        term: String,
+        room_id: Option<String>,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+/// The typed counterpart to [`Request`], tagged by `method` the same way on
+/// the way back out.
+#[derive(Debug, Serialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub(crate) enum Response {
+    Pong,
+    Version { version: String },
+    Search { results: Value },
+}
+
+/// Whether this connection has negotiated MessagePack framing in place of
+/// the default JSON. A native messaging host serves exactly one browser
+/// connection per process, so this can live as connection-global state
+/// instead of being threaded through every call site.
+static MSGPACK: AtomicBool = AtomicBool::new(false);
+
+/// Whether this connection has negotiated a trailing CRC32 checksum on every
+/// frame, to catch corruption/desync on the stdio pipe before it's handed to
+/// the decoder.
+static CHECKSUM: AtomicBool = AtomicBool::new(false);
+
+/// Reads a single frame from stdin, returning `Ok(None)` once the browser has
+/// closed the port (a clean EOF on the length prefix) instead of panicking.
+/// Any other short read is treated as a genuine protocol error.
+pub(crate) fn stdin() -> Result<Option<(i64, Request)>, Error> {
+    read_message(&mut io::stdin())
+}
+
+/// The `stdin()` logic, parameterized over the reader so it can be driven by
+/// an in-memory buffer in tests instead of the real stdin handle.
+fn read_message(reader: &mut impl Read) -> Result<Option<(i64, Request)>, Error> {
+    let data_buffer = match read_frame(reader)? {
+        Some(data_buffer) => data_buffer,
+        None => return Ok(None),
+    };
+
+    let message: Value = if MSGPACK.load(Ordering::Relaxed) {
+        rmp_serde::from_slice(&data_buffer)?
+    } else {
+        serde_json::from_slice(&data_buffer)?
+    };
+    if message.get("encoding").and_then(Value::as_str) == Some("msgpack") {
+        MSGPACK.store(true, Ordering::Relaxed);
+    }
+    if message.get("checksum").and_then(Value::as_bool) == Some(true) {
+        CHECKSUM.store(true, Ordering::Relaxed);
+    }
+    // Unlike the `method`/params decode below, a missing or non-integer
+    // `rpc_id` leaves us with no id to correlate a reply to, so it can't be
+    // downgraded to a per-`rpc_id` error reply — it has to be a real `Error`
+    // instead of the panicking `as_i64!` macro.
+    let rpc_id = message
+        .get("rpc_id")
+        .and_then(Value::as_i64)
+        .ok_or(Error::MissingRpcId)?;
+    // A missing `method` tag, or one whose params don't match its variant's
+    // shape, is a malformed frame rather than a host bug: fall back to
+    // `Request::Unknown` so the caller can reply with a structured error for
+    // this one `rpc_id` instead of the decode error tearing down `run()`'s
+    // whole dispatch loop.
+    let request = serde_json::from_value(message).unwrap_or(Request::Unknown);
+
+    Ok(Some((rpc_id, request)))
+}
+
+/// Reads the length-prefixed, optionally checksummed payload bytes for one
+/// frame, returning `Ok(None)` on a clean EOF before the length prefix.
+fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>, Error> {
     let mut buffer = [0; 4];
-    io::stdin().read_exact(&mut buffer).unwrap();
+    if !read_exact_or_eof(reader, &mut buffer)? {
+        return Ok(None);
+    }
     let mut buf = Cursor::new(&buffer);
-    let size = buf.read_u32::<NativeEndian>().unwrap();
+    let size = buf.read_u32::<NativeEndian>()?;
 
     let mut data_buffer = vec![0u8; size as usize];
-    io::stdin().read_exact(&mut data_buffer).unwrap();
-    let message: Value = serde_json::from_slice(&data_buffer).unwrap();
-    let rpc_id = as_i64!(message, "rpc_id");
+    reader.read_exact(&mut data_buffer)?;
+
+    if CHECKSUM.load(Ordering::Relaxed) {
+        let mut checksum_buffer = [0; 4];
+        reader.read_exact(&mut checksum_buffer)?;
+        let mut checksum_buf = Cursor::new(&checksum_buffer);
+        let expected = checksum_buf.read_u32::<NativeEndian>()?;
+        let actual = crc32(&data_buffer);
+        if actual != expected {
+            return Err(Error::ChecksumMismatch { expected, actual });
+        }
+    }
+
+    Ok(Some(data_buffer))
+}
+
+/// Precomputed table for the reflected (IEEE) CRC32 polynomial `0xEDB88320`,
+/// built once at compile time.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes the standard IEEE CRC32 checksum of `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc = (crc >> 8) ^ CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize];
+    }
+    crc ^ 0xFFFF_FFFF
+}
 
-    Ok((rpc_id, message))
+/// Like `Read::read_exact`, but a zero-byte read before anything has been
+/// consumed is reported as `Ok(false)` rather than an `UnexpectedEof` error,
+/// so callers can distinguish "nothing left to read" from "connection died
+/// mid-frame".
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 if read == 0 => return Ok(false),
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed mid-frame",
+                ))
+            }
+            n => read += n,
+        }
+    }
+    Ok(true)
 }
 
-pub(crate) fn stdout_reply(rpc_id: i64, reply: Value) -> Result<(), Error> {
+/// Drives the native messaging protocol, invoking `on_message` for every
+/// frame received until the browser closes the port.
+pub(crate) fn run<F>(mut on_message: F) -> Result<(), Error>
+where
+    F: FnMut(i64, Request) -> Result<(), Error>,
+{
+    loop {
+        match stdin()? {
+            Some((rpc_id, message)) => on_message(rpc_id, message)?,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// WebExtension native messaging caps a single app->browser message at 1 MB,
+/// so replies larger than this are split across multiple chunked frames.
+/// Configurable via [`set_max_frame_size`] for hosts talking to a peer with a
+/// different limit.
+static MAX_FRAME_SIZE: AtomicUsize = AtomicUsize::new(1024 * 1024);
+
+/// Overrides the frame size (in bytes) above which `stdout_reply` splits a
+/// reply into chunks. Defaults to 1 MiB, the WebExtension native-messaging
+/// cap on a single app->browser message.
+pub(crate) fn set_max_frame_size(bytes: usize) {
+    MAX_FRAME_SIZE.store(bytes, Ordering::Relaxed);
+}
+
+fn max_frame_size() -> usize {
+    MAX_FRAME_SIZE.load(Ordering::Relaxed)
+}
+
+/// Bytes reserved out of the frame size budget for the `chunk_index`/
+/// `chunk_count` digits, whose width isn't known until the final chunk count
+/// is decided.
+const CHUNK_INDEX_MARGIN: usize = 32;
+
+/// Sends an intermediate, non-terminal update for a long-running `rpc_id`
+/// (e.g. "indexed N of M events"). Any number of progress frames may precede
+/// the terminal [`stdout_reply`] or [`stdout_error`] for the same `rpc_id`.
+pub(crate) fn stdout_progress(rpc_id: i64, progress: Value) -> Result<(), Error> {
     stdout(json!({
         "rpc_id": rpc_id,
-        "reply": reply,
+        "progress": progress,
+        "final": false,
     }))
 }
 
+/// Sends the terminal reply for `rpc_id`, closing out any progress frames
+/// sent for it via [`stdout_progress`].
+pub(crate) fn stdout_reply(rpc_id: i64, reply: Response) -> Result<(), Error> {
+    let reply = serde_json::to_value(&reply)?;
+    let single_frame = json!({
+        "rpc_id": rpc_id,
+        "reply": reply,
+        "final": true,
+    });
+    if frame_byte_len(&single_frame)? <= max_frame_size() {
+        return stdout(single_frame);
+    }
+
+    let payload = serde_json::to_string(&reply)?;
+    let chunks = split_reply_chunks(rpc_id, &payload, max_frame_size())?;
+    let chunk_count = chunks.len();
+    for (chunk_index, chunk) in chunks.into_iter().enumerate() {
+        let is_last = chunk_index + 1 == chunk_count;
+        stdout(json!({
+            "rpc_id": rpc_id,
+            "reply_chunk": chunk,
+            "chunk_index": chunk_index,
+            "chunk_count": chunk_count,
+            "more": !is_last,
+            "final": is_last,
+        }))?;
+    }
+    Ok(())
+}
+
+/// Splits `payload` into chunks such that each chunk's wrapped, *encoded*
+/// frame (the bytes `stdout` will actually write, after JSON/MessagePack
+/// escaping and the `rpc_id`/`chunk_index`/... envelope) fits within
+/// `max_frame_size` — budgeting on the raw string length alone undercounts
+/// frames whose content needs heavy escaping.
+fn split_reply_chunks(rpc_id: i64, payload: &str, max_frame_size: usize) -> Result<Vec<String>, Error> {
+    let budget = max_frame_size.saturating_sub(CHUNK_INDEX_MARGIN).max(1);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < payload.len() {
+        // Seed `end` near the target size instead of at the full remaining
+        // payload: re-serializing the whole remainder just to discover it's
+        // too big and shrink down is O(n) per chunk, and O(n^2) overall.
+        let mut end = (start + max_frame_size).min(payload.len());
+        loop {
+            while end > start && !payload.is_char_boundary(end) {
+                end -= 1;
+            }
+            let probe = json!({
+                "rpc_id": rpc_id,
+                "reply_chunk": &payload[start..end],
+                "chunk_index": chunks.len(),
+                "chunk_count": chunks.len() + 1,
+                "more": true,
+                "final": false,
+            });
+            let size = frame_byte_len(&probe)?;
+            if size <= budget || end <= start + 1 {
+                break;
+            }
+            let overshoot = (size - budget).max(1);
+            end = end.saturating_sub(overshoot).max(start + 1);
+        }
+        chunks.push(payload[start..end].to_string());
+        start = end;
+    }
+    Ok(chunks)
+}
+
+/// Sends an error for `rpc_id`. Like [`stdout_reply`], this is terminal: it
+/// ends the reply sequence for `rpc_id`, including one started with
+/// [`stdout_progress`].
 pub(crate) fn stdout_error(rpc_id: i64, error: Error) -> Result<(), Error> {
     stdout(json!({
         "rpc_id": rpc_id,
         "error": format!("{:?}", error),
+        "final": true,
     }))
 }
 
+/// Serializes `message` with whichever framing the connection negotiated
+/// (JSON by default, MessagePack once requested).
+fn encode(message: &Value) -> Result<Vec<u8>, Error> {
+    if MSGPACK.load(Ordering::Relaxed) {
+        Ok(rmp_serde::to_vec_named(message)?)
+    } else {
+        Ok(serde_json::to_vec(message)?)
+    }
+}
+
+/// The number of bytes `message` would occupy on the wire once encoded.
+fn frame_byte_len(message: &Value) -> Result<usize, Error> {
+    Ok(encode(message)?.len())
+}
+
+/// Encodes `message` with whichever framing the connection negotiated (JSON
+/// by default, MessagePack once requested) and writes it as a
+/// length-prefixed frame.
 fn stdout(message: Value) -> Result<(), Error> {
-    let message = serde_json::to_string(&message)?;
+    let bytes = encode(&message)?;
+    write_frame(&mut io::stdout(), &bytes)
+}
+
+/// The `stdout()` write-side logic, parameterized over the writer so it can
+/// be driven by an in-memory buffer in tests instead of the real stdout
+/// handle.
+fn write_frame(writer: &mut impl Write, bytes: &[u8]) -> Result<(), Error> {
     let mut size = Vec::default();
-    size.write_u32::<NativeEndian>(message.len() as u32)?;
-    io::stdout().write(&size)?;
-    io::stdout().write(&message.into_bytes())?;
-    Ok(io::stdout().flush()?)
+    size.write_u32::<NativeEndian>(bytes.len() as u32)?;
+    writer.write_all(&size)?;
+    writer.write_all(bytes)?;
+    if CHECKSUM.load(Ordering::Relaxed) {
+        let mut checksum = Vec::default();
+        checksum.write_u32::<NativeEndian>(crc32(bytes))?;
+        writer.write_all(&checksum)?;
+    }
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // `MSGPACK`/`CHECKSUM` are process-global, so tests that toggle them
+    // serialize on this lock to avoid racing with each other.
+    static STATE_LOCK: Mutex<()> = Mutex::new(());
+
+    fn with_checksum_enabled<T>(f: impl FnOnce() -> T) -> T {
+        let _guard = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        CHECKSUM.store(true, Ordering::Relaxed);
+        let result = f();
+        CHECKSUM.store(false, Ordering::Relaxed);
+        result
+    }
+
+    fn with_msgpack_enabled<T>(f: impl FnOnce() -> T) -> T {
+        let _guard = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        MSGPACK.store(true, Ordering::Relaxed);
+        let result = f();
+        MSGPACK.store(false, Ordering::Relaxed);
+        result
+    }
+
+    #[test]
+    fn crc32_matches_known_test_vector() {
+        // The canonical CRC32/IEEE check value for the ASCII string "123456789".
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn crc32_of_empty_input_is_zero() {
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn split_reply_chunks_reassembles_to_the_original_payload() {
+        let payload = "x".repeat(10_000);
+        let chunks = split_reply_chunks(1, &payload, 512).unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), payload);
+    }
+
+    #[test]
+    fn split_reply_chunks_keeps_each_encoded_frame_within_budget() {
+        // Heavy on characters that expand under JSON escaping, so a split
+        // based on raw string length alone would overshoot the budget.
+        let payload = "\"\\\n\t".repeat(2_000);
+        let max_frame_size = 512;
+        let chunks = split_reply_chunks(1, &payload, max_frame_size).unwrap();
+        let chunk_count = chunks.len();
+        for (chunk_index, chunk) in chunks.iter().enumerate() {
+            let is_last = chunk_index + 1 == chunk_count;
+            let frame = json!({
+                "rpc_id": 1,
+                "reply_chunk": chunk,
+                "chunk_index": chunk_index,
+                "chunk_count": chunk_count,
+                "more": !is_last,
+                "final": is_last,
+            });
+            assert!(frame_byte_len(&frame).unwrap() <= max_frame_size);
+        }
+        assert_eq!(chunks.concat(), payload);
+    }
+
+    #[test]
+    fn read_frame_returns_none_on_clean_eof() {
+        let mut reader = Cursor::new(Vec::<u8>::new());
+        assert!(read_frame(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn write_frame_then_read_frame_roundtrips() {
+        let payload = b"hello world".to_vec();
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &payload).unwrap();
+
+        let mut reader = Cursor::new(buf);
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), payload);
+    }
+
+    #[test]
+    fn write_frame_then_read_frame_roundtrips_with_checksum() {
+        with_checksum_enabled(|| {
+            let payload = b"checked payload".to_vec();
+            let mut buf = Vec::new();
+            write_frame(&mut buf, &payload).unwrap();
+
+            let mut reader = Cursor::new(buf);
+            assert_eq!(read_frame(&mut reader).unwrap().unwrap(), payload);
+        });
+    }
+
+    #[test]
+    fn read_frame_rejects_a_corrupted_checksum() {
+        with_checksum_enabled(|| {
+            let payload = b"checked payload".to_vec();
+            let mut buf = Vec::new();
+            write_frame(&mut buf, &payload).unwrap();
+            *buf.last_mut().unwrap() ^= 0xFF;
+
+            let mut reader = Cursor::new(buf);
+            assert!(matches!(
+                read_frame(&mut reader),
+                Err(Error::ChecksumMismatch { .. })
+            ));
+        });
+    }
+
+    #[test]
+    fn write_frame_then_read_frame_roundtrips_with_msgpack() {
+        with_msgpack_enabled(|| {
+            let message = json!({"rpc_id": 5, "reply": {"pong": true}, "final": true});
+            let bytes = encode(&message).unwrap();
+
+            let mut buf = Vec::new();
+            write_frame(&mut buf, &bytes).unwrap();
+
+            let mut reader = Cursor::new(buf);
+            let data_buffer = read_frame(&mut reader).unwrap().unwrap();
+            let decoded: Value = rmp_serde::from_slice(&data_buffer).unwrap();
+            assert_eq!(decoded, message);
+        });
+    }
+
+    #[test]
+    fn read_message_negotiates_msgpack_from_the_first_frame_and_decodes_the_rest_as_msgpack() {
+        let _guard = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        MSGPACK.store(false, Ordering::Relaxed);
+
+        // The negotiating frame itself is still plain JSON.
+        let mut buf = Vec::new();
+        write_frame(
+            &mut buf,
+            serde_json::to_string(&json!({"rpc_id": 1, "method": "ping", "encoding": "msgpack"}))
+                .unwrap()
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut reader = Cursor::new(buf);
+        let (rpc_id, request) = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(rpc_id, 1);
+        assert!(matches!(request, Request::Ping));
+        assert!(MSGPACK.load(Ordering::Relaxed));
+
+        // Every frame after negotiation is MessagePack.
+        let mut buf = Vec::new();
+        write_frame(
+            &mut buf,
+            &rmp_serde::to_vec_named(&json!({"rpc_id": 2, "method": "version"})).unwrap(),
+        )
+        .unwrap();
+        let mut reader = Cursor::new(buf);
+        let (rpc_id, request) = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(rpc_id, 2);
+        assert!(matches!(request, Request::Version));
+
+        MSGPACK.store(false, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn read_message_decodes_a_known_method() {
+        let mut buf = Vec::new();
+        write_frame(
+            &mut buf,
+            serde_json::to_string(&json!({"rpc_id": 7, "method": "ping"}))
+                .unwrap()
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let mut reader = Cursor::new(buf);
+        let (rpc_id, request) = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(rpc_id, 7);
+        assert!(matches!(request, Request::Ping));
+    }
+
+    #[test]
+    fn read_message_rejects_a_frame_with_no_rpc_id() {
+        let mut buf = Vec::new();
+        write_frame(
+            &mut buf,
+            serde_json::to_string(&json!({"method": "ping"}))
+                .unwrap()
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let mut reader = Cursor::new(buf);
+        assert!(matches!(
+            read_message(&mut reader),
+            Err(Error::MissingRpcId)
+        ));
+    }
+
+    #[test]
+    fn read_message_falls_back_to_unknown_on_malformed_params() {
+        let mut buf = Vec::new();
+        write_frame(
+            &mut buf,
+            // `search` requires a `term`, which is missing here.
+            serde_json::to_string(&json!({"rpc_id": 9, "method": "search"}))
+                .unwrap()
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let mut reader = Cursor::new(buf);
+        let (rpc_id, request) = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(rpc_id, 9);
+        assert!(matches!(request, Request::Unknown));
+    }
 }